@@ -13,16 +13,50 @@
 //! # }
 //! ```
 
-use anyhow::Context;
 use serde_derive::Deserialize;
-use std::process::Command;
+use std::collections::HashMap;
+use std::process::{Command, Output};
 use thiserror::Error;
 
-/// Our generic catchall fatal error, expected to be converted
-/// to a string to output to a terminal or logs.
+pub mod transaction;
+
+/// Errors that can occur running or parsing the output of rpm-ostree.
 #[derive(Error, Debug)]
-#[error("{0}")]
-pub struct Error(String);
+pub enum Error {
+    /// A likely-transient failure invoking rpm-ostree, e.g. an activation
+    /// failure (see <https://github.com/coreos/rpm-ostree/issues/2531>).
+    /// A failed run is returned as this once the configured `RetryPolicy`
+    /// has been exhausted; a failure to spawn the binary (other than it
+    /// not being found) is returned as this at once, without retrying.
+    /// Retrying later (e.g. after a longer backoff) may succeed.
+    #[error("running rpm-ostree failed: {0}")]
+    Transient(String),
+    /// rpm-ostree ran successfully but its output couldn't be parsed.
+    #[error("failed to parse rpm-ostree output: {0}")]
+    Parse(String),
+    /// This system doesn't appear to be managed by rpm-ostree at all, e.g.
+    /// it has been rebased into a plain container image, or is an ordinary
+    /// package-based host. Unlike `Transient`, retrying will not help;
+    /// callers such as update agents should treat this as a reason to exit
+    /// cleanly rather than crash-loop.
+    #[error("system is not managed by rpm-ostree (not booted via ostree, or rpm-ostree is not installed)")]
+    SystemNotRpmOstree,
+}
+
+/// Best-effort substrings rpm-ostree is known to print on stderr when the
+/// running system isn't managed by it at all, as opposed to a transient
+/// activation failure.
+const NOT_RPM_OSTREE_MARKERS: &[&str] = &[
+    "this system was not booted via libostree",
+    "not booted via libostree",
+];
+
+fn looks_like_not_rpm_ostree(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    NOT_RPM_OSTREE_MARKERS
+        .iter()
+        .any(|marker| stderr.contains(marker))
+}
 
 /// Representation of the rpm-ostree client-side state; this
 /// can be parsed directly from the output of `rpm-ostree status --json`.
@@ -45,39 +79,294 @@ pub struct Deployment {
     pub booted: bool,
     pub serial: u32,
     pub origin: String,
+    /// Arbitrary metadata from the commit this deployment is based on,
+    /// e.g. `rpmostree.inputhash`, `version`, or downstream keys such
+    /// as `fedora-coreos.stream`. This is intentionally an untyped map;
+    /// this crate doesn't know about (and shouldn't hardcode) keys that
+    /// are only meaningful to a particular distro or consumer.
+    #[serde(default)]
+    pub base_commit_meta: HashMap<String, serde_json::Value>,
+    /// Unique identifier for this deployment, distinct from the checksum.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The human-readable version string, if the commit has one.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Unix timestamp (seconds) of the commit this deployment is based on.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Packages layered on top of the base via `rpm-ostree install`.
+    #[serde(default)]
+    pub requested_packages: Vec<String>,
+    /// The explicitly requested layered package names. `status --json` does
+    /// not expose the dependency-resolved closure, only what was asked for.
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Local RPM packages layered via `rpm-ostree install <path-to-rpm>`.
+    #[serde(default)]
+    pub requested_local_packages: Vec<String>,
+    /// Base packages explicitly requested for removal via `rpm-ostree override remove`.
+    #[serde(default)]
+    pub requested_base_removals: Vec<String>,
+    /// The fully resolved set of base packages removed.
+    #[serde(default)]
+    pub base_removals: Vec<String>,
+    /// Paths under `/etc` forced into the deployment's initramfs via
+    /// `rpm-ostree initramfs-etc --track`.
+    #[serde(default)]
+    pub initramfs_etc: Vec<String>,
+    /// Whether GPG signature verification is enabled for this deployment's origin.
+    #[serde(default)]
+    pub gpg_enabled: Option<bool>,
+    /// Whether the initramfs was regenerated locally (e.g. via `rpm-ostree initramfs`).
+    #[serde(default)]
+    pub regenerate_initramfs: Option<bool>,
+    /// The checksum of the base commit, when this is a layered deployment
+    /// whose base differs from `checksum` (which is the final, layered commit).
+    #[serde(default)]
+    pub base_checksum: Option<String>,
+}
+
+impl Deployment {
+    /// Look up a string-valued key in `base_commit_meta`.
+    pub fn base_commit_meta_string(&self, key: &str) -> Option<&str> {
+        self.base_commit_meta.get(key).and_then(|v| v.as_str())
+    }
+}
+
+/// Policy governing how many times to try a command in the face of
+/// transient activation failures, and how long to pause between attempts.
+/// `max_retries` counts every attempt, including the first one, so a
+/// value of `1` means no retries.
+///
+/// See <https://github.com/coreos/rpm-ostree/issues/2531>.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub pause: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            pause: std::time::Duration::from_secs(1),
+        }
+    }
 }
 
-/// Gather a snapshot of the system status.
-fn impl_query_status() -> anyhow::Result<Status> {
-    // Retry on temporary activation failures, see
-    // https://github.com/coreos/rpm-ostree/issues/2531
-    let pause = std::time::Duration::from_secs(1);
-    let max_retries = 10;
-    let mut retries = 0;
-    let cmd_res = loop {
-        retries += 1;
-        let res = Command::new("rpm-ostree")
-            .args(&["status", "--json"])
-            .output()
-            .context("failed to spawn 'rpm-ostree status'")?;
-
-        if res.status.success() || retries >= max_retries {
-            break res;
+impl RetryPolicy {
+    /// Disable retries entirely; a single failure is returned immediately.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 1,
+            pause: std::time::Duration::from_secs(0),
+        }
+    }
+}
+
+/// A configurable client for invoking rpm-ostree. Use this rather than
+/// the free functions in this crate when you need to point at a
+/// non-default binary (e.g. in tests) or sysroot, or to tune the
+/// retry behavior.
+#[derive(Debug, Clone)]
+pub struct Client {
+    exe: std::ffi::OsString,
+    /// The `ostree` binary, used for the handful of operations (like
+    /// deployment pinning) that are verbs of `ostree admin` rather than
+    /// `rpm-ostree`.
+    ostree_exe: std::ffi::OsString,
+    sysroot: Option<std::path::PathBuf>,
+    envs: Vec<(std::ffi::OsString, std::ffi::OsString)>,
+    retry: RetryPolicy,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self {
+            exe: "rpm-ostree".into(),
+            ostree_exe: "ostree".into(),
+            sysroot: None,
+            envs: Vec::new(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl Client {
+    /// Create a client with the default configuration (the `rpm-ostree`
+    /// binary found on `$PATH`, the default sysroot, and the standard
+    /// retry policy).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use a specific rpm-ostree binary rather than looking one up on `$PATH`.
+    pub fn exe(mut self, path: impl Into<std::ffi::OsString>) -> Self {
+        self.exe = path.into();
+        self
+    }
+
+    /// Use a specific `ostree` binary rather than looking one up on `$PATH`.
+    pub fn ostree_exe(mut self, path: impl Into<std::ffi::OsString>) -> Self {
+        self.ostree_exe = path.into();
+        self
+    }
+
+    /// Operate on a sysroot other than `/`, passed to rpm-ostree as `--sysroot`.
+    pub fn sysroot(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.sysroot = Some(path.into());
+        self
+    }
+
+    /// Set an environment variable on the spawned rpm-ostree process.
+    pub fn env(
+        mut self,
+        key: impl Into<std::ffi::OsString>,
+        value: impl Into<std::ffi::OsString>,
+    ) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Override the default retry policy for transient activation failures.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Build a `Command` for the given binary and arguments, with
+    /// `--sysroot` and environment variables applied.
+    fn command_for<I, S>(&self, exe: &std::ffi::OsStr, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let mut cmd = Command::new(exe);
+        cmd.args(args);
+        if let Some(sysroot) = &self.sysroot {
+            cmd.arg("--sysroot").arg(sysroot);
+        }
+        for (k, v) in &self.envs {
+            cmd.env(k, v);
+        }
+        cmd
+    }
+
+    /// Build a `Command` for the given rpm-ostree subcommand and arguments.
+    fn command<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        self.command_for(&self.exe, args)
+    }
+
+    /// Run the given subcommand and arguments against `exe`, retrying on
+    /// transient activation failures per `self.retry`, and classifying
+    /// a terminal failure as `Error::SystemNotRpmOstree` when possible.
+    fn run_exe<I, S>(&self, exe: &std::ffi::OsStr, args: I) -> Result<Output, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let args: Vec<_> = args.into_iter().collect();
+        let mut retries = 0;
+        loop {
+            retries += 1;
+            let res = self.command_for(exe, &args).output();
+            let res = match res {
+                Ok(res) => res,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Error::SystemNotRpmOstree)
+                }
+                Err(e) => {
+                    return Err(Error::Transient(format!(
+                        "failed to spawn '{}': {}",
+                        exe.to_string_lossy(),
+                        e
+                    )))
+                }
+            };
+
+            if res.status.success() {
+                return Ok(res);
+            }
+
+            // Don't waste the retry loop on a host that isn't managed by
+            // rpm-ostree at all; that's a terminal condition, not a
+            // transient activation failure.
+            let stderr = String::from_utf8_lossy(&res.stderr);
+            if looks_like_not_rpm_ostree(&stderr) {
+                return Err(Error::SystemNotRpmOstree);
+            }
+
+            if retries >= self.retry.max_retries {
+                return Err(Error::Transient(stderr.into_owned()));
+            }
+            std::thread::sleep(self.retry.pause);
         }
-        std::thread::sleep(pause);
-    };
+    }
+
+    /// Run the given rpm-ostree subcommand and arguments. See `run_exe`.
+    fn run<I, S>(&self, args: I) -> Result<Output, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let exe = self.exe.clone();
+        self.run_exe(&exe, args)
+    }
+
+    /// Run the given `ostree` subcommand and arguments. See `run_exe`.
+    fn run_ostree<I, S>(&self, args: I) -> Result<Output, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let exe = self.ostree_exe.clone();
+        self.run_exe(&exe, args)
+    }
+
+    /// Run `rpm-ostree status --json`, optionally with extra arguments.
+    fn impl_query_status(&self, extra_args: &[&str]) -> Result<Status, Error> {
+        let mut args = vec!["status", "--json"];
+        args.extend_from_slice(extra_args);
+        let cmd_res = self.run(args)?;
+        serde_json::from_slice(&cmd_res.stdout).map_err(|e| Error::Parse(e.to_string()))
+    }
 
-    if !cmd_res.status.success() {
-        anyhow::bail!(
-            "running 'rpm-ostree status' failed: {}",
-            String::from_utf8_lossy(&cmd_res.stderr)
-        )
+    /// Gather a snapshot of the system status.
+    pub fn query_status(&self) -> Result<Status, Error> {
+        self.impl_query_status(&[])
     }
 
-    Ok(serde_json::from_slice(&cmd_res.stdout)
-        .context("failed to parse 'rpm-ostree status' output")?)
+    /// Gather just the currently booted deployment, skipping any others.
+    /// This is cheaper than `query_status()` for callers that only care
+    /// about the running system, and errors out if rpm-ostree doesn't
+    /// report a booted deployment (which shouldn't normally happen).
+    pub fn query_booted(&self) -> Result<Deployment, Error> {
+        let mut status = self.impl_query_status(&["--booted"])?;
+        let deployment = status.deployments.pop().ok_or_else(|| {
+            Error::Parse(
+                "expected exactly one deployment from 'rpm-ostree status --booted'".to_string(),
+            )
+        })?;
+        if !deployment.booted {
+            return Err(Error::Parse(
+                "'rpm-ostree status --booted' returned a non-booted deployment".to_string(),
+            ));
+        }
+        Ok(deployment)
+    }
 }
 
+/// Gather a snapshot of the system status, using the default `Client`.
 pub fn query_status() -> Result<Status, Error> {
-    impl_query_status().map_err(|e| Error(e.to_string()))
+    Client::default().query_status()
+}
+
+/// Gather just the currently booted deployment, using the default `Client`.
+pub fn query_booted() -> Result<Deployment, Error> {
+    Client::default().query_booted()
 }
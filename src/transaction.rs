@@ -0,0 +1,112 @@
+//! Mutating rpm-ostree operations: upgrade, deploy, rebase, rollback,
+//! and pin/unpin. These share the `Client` used for read-only status
+//! queries, including its retry-on-activation-failure behavior.
+
+use crate::{Client, Error};
+
+/// The outcome of a mutating rpm-ostree transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// The requested state was already the current state; nothing changed.
+    NoOp,
+    /// A new deployment was staged; it will be applied on the next boot.
+    Staged,
+    /// A new deployment was created and is already applied; a reboot
+    /// is required for it to take effect.
+    RequiresReboot,
+    /// The transaction succeeded, but its output didn't match any of the
+    /// phrasings above, so the actual outcome couldn't be classified.
+    /// This is scraped from rpm-ostree's human-readable output, which is
+    /// not a stable API, so callers should treat this the same as
+    /// `RequiresReboot` (check, don't assume) rather than as a no-op.
+    Unknown,
+}
+
+impl TransactionOutcome {
+    /// Classify the outcome of a transaction from its process output.
+    fn from_output(output: std::process::Output) -> Result<Self, Error> {
+        let combined = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(Self::parse(&combined))
+    }
+
+    /// Classify the outcome of a transaction from its combined stdout/stderr,
+    /// matching on the same phrases rpm-ostree itself prints to the terminal.
+    fn parse(output: &str) -> Self {
+        let output = output.to_lowercase();
+        if output.contains("no upgrade available")
+            || output.contains("no change")
+            || output.contains("already pinned")
+            || output.contains("already unpinned")
+        {
+            Self::NoOp
+        } else if output.contains("changes queued for next boot")
+            || output.contains("staging deployment")
+            || output.contains("run \"systemctl reboot\"")
+        {
+            Self::Staged
+        } else if output.contains("requires a reboot")
+            || output.contains("changes will take effect after reboot")
+        {
+            Self::RequiresReboot
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl Client {
+    /// Run `rpm-ostree upgrade`.
+    pub fn upgrade(&self) -> Result<TransactionOutcome, Error> {
+        self.run_transaction(["upgrade"])
+    }
+
+    /// Run `rpm-ostree deploy <version>`.
+    pub fn deploy(&self, version: &str) -> Result<TransactionOutcome, Error> {
+        self.run_transaction(["deploy", version])
+    }
+
+    /// Run `rpm-ostree rebase <refspec>`.
+    pub fn rebase(&self, refspec: &str) -> Result<TransactionOutcome, Error> {
+        self.run_transaction(["rebase", refspec])
+    }
+
+    /// Run `rpm-ostree rollback`.
+    pub fn rollback(&self) -> Result<TransactionOutcome, Error> {
+        self.run_transaction(["rollback"])
+    }
+
+    /// Pin the deployment at `index` (as shown in `rpm-ostree status`),
+    /// preventing it from being garbage-collected. Deployment pinning is
+    /// an `ostree admin` verb, not an `rpm-ostree` one, so this shells out
+    /// to the `ostree` binary configured on this `Client`.
+    pub fn pin(&self, index: u32) -> Result<TransactionOutcome, Error> {
+        let index = index.to_string();
+        self.run_ostree_transaction(["admin", "pin", index.as_str()])
+    }
+
+    /// Unpin the deployment at `index`.
+    pub fn unpin(&self, index: u32) -> Result<TransactionOutcome, Error> {
+        let index = index.to_string();
+        self.run_ostree_transaction(["admin", "pin", "--unpin", index.as_str()])
+    }
+
+    fn run_transaction<I, S>(&self, args: I) -> Result<TransactionOutcome, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        TransactionOutcome::from_output(self.run(args)?)
+    }
+
+    fn run_ostree_transaction<I, S>(&self, args: I) -> Result<TransactionOutcome, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        TransactionOutcome::from_output(self.run_ostree(args)?)
+    }
+}